@@ -1,24 +1,69 @@
+use std::collections::BTreeMap;
+
 use kay::World;
 use compact::CVec;
 use descartes::{N, P2, V2, Band, Segment, Path, FiniteCurve, Shape, SimpleShape, clipper,
                 Intersect};
 use monet::{RendererID, Instance};
 use stagemaster::geometry::{band_to_geometry, CPath, CShape};
-use itertools::Itertools;
 use style::colors;
 use ordered_float::OrderedFloat;
 
 use planning_new::{Plan, GestureIntent, PlanResult, Prototype};
 
+// chosen how the gesture's points are turned into a smooth centerline path
+#[derive(Compact, Clone)]
+pub enum RoadDrawingMode {
+    // chain straight segments, smoothing each corner with a circular arc fillet
+    StraightWithCornerArcs,
+    // interpolate a single curve that respects the tangent at the start
+    // (and optionally the end), for deliberately sweeping curves
+    CurvedWithTangent { start_tangent: V2, end_tangent: Option<V2> },
+}
+
+#[derive(Compact, Clone, Copy, PartialEq)]
+pub enum LaneType {
+    Driving,
+    Parking,
+    Sidewalk,
+    Shoulder,
+}
+
 #[derive(Compact, Clone)]
 pub struct RoadIntent {
-    n_lanes_forward: u8,
-    n_lanes_backward: u8,
+    lane_types_forward: CVec<LaneType>,
+    lane_types_backward: CVec<LaneType>,
+    drawing_mode: RoadDrawingMode,
 }
 
 impl RoadIntent {
     pub fn new(n_lanes_forward: u8, n_lanes_backward: u8) -> Self {
-        RoadIntent { n_lanes_forward, n_lanes_backward }
+        RoadIntent {
+            lane_types_forward: vec![LaneType::Driving; n_lanes_forward as usize].into(),
+            lane_types_backward: vec![LaneType::Driving; n_lanes_backward as usize].into(),
+            drawing_mode: RoadDrawingMode::StraightWithCornerArcs,
+        }
+    }
+
+    pub fn new_curved(
+        n_lanes_forward: u8,
+        n_lanes_backward: u8,
+        start_tangent: V2,
+        end_tangent: Option<V2>,
+    ) -> Self {
+        RoadIntent {
+            lane_types_forward: vec![LaneType::Driving; n_lanes_forward as usize].into(),
+            lane_types_backward: vec![LaneType::Driving; n_lanes_backward as usize].into(),
+            drawing_mode: RoadDrawingMode::CurvedWithTangent { start_tangent, end_tangent },
+        }
+    }
+
+    pub fn with_lane_types(
+        lane_types_forward: CVec<LaneType>,
+        lane_types_backward: CVec<LaneType>,
+        drawing_mode: RoadDrawingMode,
+    ) -> Self {
+        RoadIntent { lane_types_forward, lane_types_backward, drawing_mode }
     }
 }
 
@@ -26,13 +71,19 @@ impl RoadIntent {
 pub enum RoadPrototype {
     Lane(LanePrototype),
     Intersection(IntersectionPrototype),
+    SidewalkCorner(CShape),
 }
 
+// the second field is the elevation sampled at evenly spaced distances along
+// the path, one sample per `ELEVATION_SAMPLE_DISTANCE` plus the endpoints
 #[derive(Compact, Clone)]
-pub struct LanePrototype(CPath);
+pub struct LanePrototype(CPath, CVec<N>, LaneType);
 
+// the fourth field is the index of the road (in gesture order) this
+// connector belongs to, so corner-generation can tell two connectors of the
+// same road apart from connectors of two different roads meeting at a junction
 #[derive(Compact, Clone)]
-pub struct IntersectionConnector(P2, V2);
+pub struct IntersectionConnector(P2, V2, LaneType, usize);
 
 #[derive(Compact, Clone)]
 pub struct IntersectionPrototype {
@@ -41,17 +92,508 @@ pub struct IntersectionPrototype {
     outgoing: CVec<IntersectionConnector>,
     connecting_lanes: CVec<LanePrototype>,
     timings: CVec<CVec<bool>>,
+    elevation: N,
 }
 
 const LANE_WIDTH: N = 6.0;
 const LANE_DISTANCE: N = 0.8 * LANE_WIDTH;
 const CENTER_LANE_DISTANCE: N = LANE_DISTANCE;
 
-pub fn calculate_prototypes(plan: &Plan) -> Vec<Prototype> {
+// movements whose incoming direction points almost directly away from the
+// outgoing connector are treated as U-turns and only generated as a last resort
+const UTURN_DOT_THRESHOLD: N = -0.9;
+
+// a single direct arc is only used if it arrives within about 26° of the
+// outgoing connector's direction
+const DIRECT_ARC_MIN_END_DOT: N = 0.9;
+
+fn connecting_path(p_in: P2, dir_in: V2, p_out: P2, dir_out: V2) -> Option<CPath> {
+    if let Some(direct_arc) = Segment::arc_with_direction(p_in, dir_in, p_out) {
+        let arc_end_direction = direct_arc.direction_along(direct_arc.length());
+        if arc_end_direction.dot(&dir_out) > DIRECT_ARC_MIN_END_DOT {
+            if let Some(path) = CPath::new(vec![direct_arc]).ok() {
+                return Some(path);
+            }
+        }
+    }
+
+    // no single arc respects both tangents - approximate a cubic-style curve
+    // through two control points pulled out along each connector's direction
+    let offset = (p_out - p_in).norm() / 3.0;
+    let control_1 = p_in + dir_in * offset;
+    let control_2 = p_out - dir_out * offset;
+
+    let mut segments = Vec::new();
+
+    if let Some(entry) = Segment::arc_with_direction(p_in, dir_in, control_1).or_else(|| {
+        Segment::line(p_in, control_1)
+    })
+    {
+        segments.push(entry);
+    }
+
+    if let Some(middle) = Segment::line(control_1, control_2) {
+        segments.push(middle);
+    }
+
+    if let Some(exit) = Segment::arc_with_direction(p_out, -dir_out, control_2)
+        .map(|arc| arc.reverse())
+        .or_else(|| Segment::line(control_2, p_out))
+    {
+        segments.push(exit);
+    }
+
+    // if the control-point curve still couldn't be built (e.g. a degenerate
+    // middle segment when p_in and p_out are very close), fall back to a
+    // straight line so any two distinct points always produce a connecting
+    // lane, rather than silently vanishing at the caller
+    CPath::new(segments).ok().or_else(|| {
+        Segment::line(p_in, p_out).and_then(|segment| CPath::new(vec![segment]).ok())
+    })
+}
+
+// build a single sweeping curve through `points`, starting at `start_tangent`
+// and carrying the outgoing tangent of each arc into the next, rather than
+// chaining straight segments smoothed with corner fillets
+fn curved_path(points: &[P2], start_tangent: V2, end_tangent: Option<V2>) -> Option<CPath> {
+    if points.len() < 2 {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    let mut previous_point = points[0];
+    let mut previous_tangent = start_tangent;
+
+    for (i, &next_point) in points.iter().enumerate().skip(1) {
+        let is_last_point = i == points.len() - 1;
+
+        if is_last_point {
+            if let Some(end_tangent) = end_tangent {
+                let end_curve =
+                    connecting_path(previous_point, previous_tangent, next_point, end_tangent);
+
+                if let Some(end_curve) = end_curve {
+                    segments.extend(end_curve.segments().iter().cloned());
+                    break;
+                }
+            }
+        }
+
+        match Segment::arc_with_direction(previous_point, previous_tangent, next_point) {
+            Some(arc) => {
+                previous_tangent = arc.direction_along(arc.length());
+                segments.push(arc);
+            }
+            None => {
+                // the tangent can't be honored for this gap (e.g. a
+                // degenerate arc) - fall back to a straight line so the path
+                // stays continuous instead of leaving a gap that CPath::new
+                // would reject, silently dropping the whole gesture
+                if let Some(line) = Segment::line(previous_point, next_point) {
+                    previous_tangent = (next_point - previous_point).normalize();
+                    segments.push(line);
+                }
+            }
+        }
+
+        previous_point = next_point;
+    }
+
+    CPath::new(segments).ok()
+}
+
+// one height sample per this many meters of path length - dense enough that
+// lane geometry closely follows terrain without sampling every vertex
+const ELEVATION_SAMPLE_DISTANCE: N = 5.0;
+
+fn elevation_sample_count(path: &CPath) -> usize {
+    ((path.length() / ELEVATION_SAMPLE_DISTANCE).ceil() as usize).max(1)
+}
+
+fn elevation_profile_for<F: Fn(P2) -> N>(path: &CPath, height_at: &F) -> CVec<N> {
+    let n_samples = elevation_sample_count(path);
+    let length = path.length();
+
+    (0..=n_samples)
+        .map(|i| height_at(path.along((i as N / n_samples as N) * length)))
+        .collect()
+}
+
+// the intersection surface doesn't vary within itself, so connecting lanes
+// simply interpolate between the (here: equal) boundary heights snapped at
+// the junction's incoming/outgoing connectors
+fn flat_elevation_profile(path: &CPath, elevation: N) -> CVec<N> {
+    (0..=elevation_sample_count(path)).map(|_| elevation).collect()
+}
+
+fn intersection_elevation_containing(intersections: &[Prototype], point: P2) -> Option<N> {
+    intersections
+        .iter()
+        .filter_map(|prototype| match *prototype {
+            Prototype::Road(RoadPrototype::Intersection(ref intersection))
+                if intersection.shape.contains(point) => Some(intersection.elevation),
+            _ => None,
+        })
+        .next()
+}
+
+// a lane's trimmed ends sit exactly on an intersection's boundary, so their
+// elevation samples are replaced with the intersection's own elevation -
+// otherwise the lane and the junction surface it meets would not quite line up
+fn snap_elevation_profile_ends(
+    profile: CVec<N>,
+    path: &CPath,
+    intersections: &[Prototype],
+) -> CVec<N> {
+    let mut samples = profile.into_iter().collect::<Vec<_>>();
+
+    if let Some(first) = samples.first_mut() {
+        if let Some(elevation) = intersection_elevation_containing(intersections, path.start()) {
+            *first = elevation;
+        }
+    }
+
+    if let Some(last) = samples.last_mut() {
+        if let Some(elevation) = intersection_elevation_containing(intersections, path.end()) {
+            *last = elevation;
+        }
+    }
+
+    samples.into()
+}
+
+fn connecting_lanes_for(
+    incoming: &CVec<IntersectionConnector>,
+    outgoing: &CVec<IntersectionConnector>,
+    elevation: N,
+) -> CVec<LanePrototype> {
+    // turn movements only connect driving lanes - pedestrians cross via
+    // sidewalk corners instead
+    let incoming = incoming
+        .iter()
+        .filter(|connector| connector.2 == LaneType::Driving)
+        .cloned()
+        .collect::<Vec<_>>();
+    let outgoing = outgoing
+        .iter()
+        .filter(|connector| connector.2 == LaneType::Driving)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut connections = Vec::new();
+
+    for (in_i, &IntersectionConnector(p_in, dir_in, _, _)) in incoming.iter().enumerate() {
+        for (out_i, &IntersectionConnector(p_out, _, _, _)) in outgoing.iter().enumerate() {
+            let towards_out = (p_out - p_in).normalize();
+
+            if dir_in.dot(&towards_out) > UTURN_DOT_THRESHOLD {
+                connections.push((in_i, out_i));
+            }
+        }
+    }
+
+    // every incoming connector must originate at least one connecting lane,
+    // even if the closest available outgoing connector requires a U-turn
+    for (in_i, &IntersectionConnector(p_in, _, _, _)) in incoming.iter().enumerate() {
+        if !connections.iter().any(|&(i, _)| i == in_i) {
+            if let Some((closest_out_i, _)) = outgoing.iter().enumerate().min_by_key(
+                |&(_, &IntersectionConnector(p_out, _, _, _))| OrderedFloat((p_out - p_in).norm()),
+            )
+            {
+                connections.push((in_i, closest_out_i));
+            }
+        }
+    }
+
+    // ...and every outgoing connector is the destination of at least one
+    for (out_i, &IntersectionConnector(p_out, _, _, _)) in outgoing.iter().enumerate() {
+        if !connections.iter().any(|&(_, o)| o == out_i) {
+            if let Some((closest_in_i, _)) = incoming.iter().enumerate().min_by_key(
+                |&(_, &IntersectionConnector(p_in, _, _, _))| OrderedFloat((p_out - p_in).norm()),
+            )
+            {
+                connections.push((closest_in_i, out_i));
+            }
+        }
+    }
+
+    connections
+        .into_iter()
+        .filter_map(|(in_i, out_i)| {
+            let IntersectionConnector(p_in, dir_in, _, _) = incoming[in_i];
+            let IntersectionConnector(p_out, dir_out, _, _) = outgoing[out_i];
+            connecting_path(p_in, dir_in, p_out, dir_out).map(|path| {
+                let elevation_profile = flat_elevation_profile(&path, elevation);
+                LanePrototype(path, elevation_profile, LaneType::Driving)
+            })
+        })
+        .collect()
+}
+
+// movements whose heading turns clockwise by an angle in this range are
+// treated as right-turn-like and are given a green light in every phase,
+// since they rarely conflict with anything but pedestrians. straight-through
+// movements (near-zero turn) and U-turns (near-180°) are excluded
+const RIGHT_TURN_MIN_ANGLE: N = 0.3;
+const RIGHT_TURN_MAX_ANGLE: N = 2.6;
+const CONFLICT_EPSILON: N = 0.5;
+
+fn is_right_turn_like(path: &CPath) -> bool {
+    let start_direction = path.direction_along(0.0);
+    let end_direction = path.direction_along(path.length());
+
+    // the signed angle between the two headings: negative is a clockwise
+    // (right) turn, positive a counter-clockwise (left) turn
+    let cross = start_direction.x * end_direction.y - start_direction.y * end_direction.x;
+    let dot = start_direction.dot(&end_direction);
+    let turn_angle = cross.atan2(dot);
+
+    turn_angle < -RIGHT_TURN_MIN_ANGLE && turn_angle > -RIGHT_TURN_MAX_ANGLE
+}
+
+fn paths_conflict(a: &CPath, b: &CPath) -> bool {
+    (a, b).intersect().iter().any(|point| {
+        point.along_a > CONFLICT_EPSILON && point.along_a < a.length() - CONFLICT_EPSILON &&
+            point.along_b > CONFLICT_EPSILON && point.along_b < b.length() - CONFLICT_EPSILON
+    })
+}
+
+fn phase_timings_for(connecting_lanes: &CVec<LanePrototype>) -> CVec<CVec<bool>> {
+    let n = connecting_lanes.len();
+
+    if n == 0 {
+        return CVec::new();
+    }
+
+    let paths = connecting_lanes
+        .iter()
+        .map(|&LanePrototype(ref path, _, _)| path)
+        .collect::<Vec<_>>();
+    let right_turn_like = paths.iter().map(|path| is_right_turn_like(path)).collect::<Vec<_>>();
+
+    let conflicts = (0..n)
+        .map(|i| {
+            (0..n)
+                .filter(|&j| {
+                    j != i && !right_turn_like[i] && !right_turn_like[j] &&
+                        paths_conflict(paths[i], paths[j])
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // greedily color the conflict graph: each phase is a set of movements
+    // that can all have green at the same time
+    let mut phases: Vec<Vec<usize>> = Vec::new();
+
+    for i in 0..n {
+        if right_turn_like[i] {
+            continue;
+        }
+
+        let compatible_phase = phases.iter().position(|phase| {
+            phase.iter().all(|movement| !conflicts[i].contains(movement))
+        });
+
+        match compatible_phase {
+            Some(phase_idx) => phases[phase_idx].push(i),
+            None => phases.push(vec![i]),
+        }
+    }
+
+    if phases.is_empty() {
+        phases.push(Vec::new());
+    }
+
+    phases
+        .into_iter()
+        .map(|phase_movements| {
+            (0..n)
+                .map(|i| right_turn_like[i] || phase_movements.contains(&i))
+                .collect()
+        })
+        .collect()
+}
+
+// walk the sidewalk connectors around the junction in angular order and fill
+// the gap between each adjacent pair with a corner polygon, so pedestrians
+// get a continuous path through the intersection. adjacent connectors that
+// belong to the same road are skipped - they're the two sidewalks of a
+// single road passing by, not a corner between two different roads
+fn sidewalk_corner_shapes_for(intersection: &IntersectionPrototype) -> CVec<CShape> {
+    let sidewalk_points = intersection
+        .incoming
+        .iter()
+        .chain(intersection.outgoing.iter())
+        .filter(|connector| connector.2 == LaneType::Sidewalk)
+        .map(|connector| (connector.0, connector.3))
+        .collect::<Vec<_>>();
+
+    if sidewalk_points.len() < 2 {
+        return CVec::new();
+    }
+
+    let center = P2::from_coordinates(
+        sidewalk_points.iter().fold(
+            V2::new(0.0, 0.0),
+            |sum, &(point, _)| sum + point.coords,
+        ) / sidewalk_points.len() as N,
+    );
+
+    let mut ordered_points = sidewalk_points;
+    ordered_points.sort_by_key(|&(point, _)| {
+        OrderedFloat((point.y - center.y).atan2(point.x - center.x))
+    });
+
+    ordered_points
+        .iter()
+        .zip(ordered_points.iter().cycle().skip(1))
+        .filter_map(|(&(corner_a, road_a), &(corner_b, road_b))| {
+            if road_a == road_b {
+                return None;
+            }
+
+            let corner_segments = vec![
+                Segment::line(corner_a, corner_b),
+                Segment::line(corner_b, center),
+                Segment::line(center, corner_a),
+            ].into_iter()
+                .filter_map(|segment| segment)
+                .collect::<Vec<_>>();
+
+            CPath::new(corner_segments).ok().and_then(|path| CShape::new(path).ok())
+        })
+        .collect()
+}
+
+// points closer together than this are considered the same junction
+const JUNCTION_MERGE_RADIUS: N = 0.5;
+
+fn find_root(parents: &mut Vec<usize>, node: usize) -> usize {
+    if parents[node] != node {
+        parents[node] = find_root(parents, parents[node]);
+    }
+    parents[node]
+}
+
+fn union_junctions(parents: &mut Vec<usize>, a: usize, b: usize) {
+    let root_a = find_root(parents, a);
+    let root_b = find_root(parents, b);
+    if root_a != root_b {
+        parents[root_a] = root_b;
+    }
+}
+
+// merge a new overlap piece into a running list of junction shapes, unioning
+// it with any existing piece it touches instead of assuming there's only
+// ever one. nothing is ever discarded: if a union still comes back as
+// several disjoint pieces (or fails), they're all kept rather than picked
+// down to one
+fn union_shapes(existing: Vec<CShape>, new_piece: CShape) -> Vec<CShape> {
+    let mut merged = Vec::new();
+    let mut pending = vec![new_piece];
+
+    for shape in existing {
+        let mut still_pending = Vec::new();
+        let mut shape_absorbed = false;
+
+        for candidate in pending {
+            match clipper::clip(clipper::Mode::Union, &shape, &candidate) {
+                Ok(union_pieces) => {
+                    if union_pieces.len() != 1 {
+                        still_pending.push(candidate);
+                    } else {
+                        still_pending.extend(union_pieces);
+                        shape_absorbed = true;
+                    }
+                }
+                Err(_) => still_pending.push(candidate),
+            }
+        }
+
+        pending = still_pending;
+
+        if !shape_absorbed {
+            merged.push(shape);
+        }
+    }
+
+    merged.extend(pending);
+    merged
+}
+
+// cluster road endpoints into junctions, then merge the pairwise overlaps of
+// all roads incident to a junction into as few shapes as possible, instead of
+// keeping one overlap blob per road pair. roads that only touch along an edge
+// without their gesture outlines actually overlapping still won't produce a
+// junction shape here - that would need true polygon trimming, not clipping
+fn intersection_shapes_for_junctions(
+    gesture_shapes: &[CShape],
+    road_paths: &[CPath],
+) -> Vec<CShape> {
+    let endpoints = road_paths
+        .iter()
+        .flat_map(|path| vec![path.start(), path.end()])
+        .collect::<Vec<_>>();
+
+    let mut parents = (0..endpoints.len()).collect::<Vec<_>>();
+
+    for i in 0..endpoints.len() {
+        for j in (i + 1)..endpoints.len() {
+            if (endpoints[i] - endpoints[j]).norm() < JUNCTION_MERGE_RADIUS {
+                union_junctions(&mut parents, i, j);
+            }
+        }
+    }
+
+    let mut roads_by_junction: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+
+    for endpoint_i in 0..endpoints.len() {
+        let road_i = endpoint_i / 2;
+        let root = find_root(&mut parents, endpoint_i);
+        let incident_roads = roads_by_junction.entry(root).or_insert_with(Vec::new);
+        if !incident_roads.contains(&road_i) {
+            incident_roads.push(road_i);
+        }
+    }
+
+    roads_by_junction
+        .values()
+        .filter(|incident_roads| incident_roads.len() >= 2)
+        .flat_map(|incident_roads| {
+            let mut junction_shapes: Vec<CShape> = Vec::new();
+
+            for (a_i, &road_a) in incident_roads.iter().enumerate() {
+                for &road_b in incident_roads.iter().skip(a_i + 1) {
+                    let overlap_pieces = clipper::clip(
+                        clipper::Mode::Intersection,
+                        &gesture_shapes[road_a],
+                        &gesture_shapes[road_b],
+                    ).unwrap_or_else(|_| vec![]);
+
+                    for piece in overlap_pieces {
+                        junction_shapes = union_shapes(junction_shapes, piece);
+                    }
+                }
+            }
+
+            junction_shapes
+        })
+        .collect()
+}
+
+pub fn calculate_prototypes<F: Fn(P2) -> N>(plan: &Plan, height_at: F) -> Vec<Prototype> {
     let gesture_intent_smooth_paths = plan.gestures
         .pairs()
         .filter_map(|(gesture_id, gesture)| match gesture.intent {
             GestureIntent::Road(ref road_intent) if gesture.points.len() >= 2 => {
+                if let RoadDrawingMode::CurvedWithTangent { start_tangent, end_tangent } =
+                    road_intent.drawing_mode
+                {
+                    return curved_path(&gesture.points, start_tangent, end_tangent).map(|path| {
+                        (gesture_id, road_intent, path)
+                    });
+                }
 
                 let center_points = gesture
                     .points
@@ -130,12 +672,12 @@ pub fn calculate_prototypes(plan: &Plan) -> Vec<Prototype> {
         .map(|&(gesture_id, road_intent, ref path)| {
             let right_path = path.shift_orthogonally(
                 CENTER_LANE_DISTANCE / 2.0 +
-                    road_intent.n_lanes_forward as f32 * LANE_DISTANCE,
+                    road_intent.lane_types_forward.len() as N * LANE_DISTANCE,
             ).unwrap_or_else(|| path.clone())
                 .reverse();
             let left_path = path.shift_orthogonally(
                 -(CENTER_LANE_DISTANCE / 2.0 +
-                      road_intent.n_lanes_backward as f32 * LANE_DISTANCE),
+                      road_intent.lane_types_backward.len() as N * LANE_DISTANCE),
             ).unwrap_or_else(|| path.clone());
 
             let outline_segments = left_path
@@ -153,136 +695,192 @@ pub fn calculate_prototypes(plan: &Plan) -> Vec<Prototype> {
         })
         .collect::<Vec<_>>();
 
-    let intersection_shapes = gesture_shapes
+    let road_paths = gesture_intent_smooth_paths
         .iter()
-        .enumerate()
-        .cartesian_product(gesture_shapes.iter().enumerate())
-        .flat_map(|((i_a, shape_a), (i_b, shape_b))| {
-            println!("{} {}", i_a, i_a);
-            if i_a == i_b {
-                vec![]
-            } else {
-                match clipper::clip(clipper::Mode::Intersection, shape_a, shape_b) {
-                    Ok(shapes) => shapes,
-                    Err(err) => {
-                        println!("Intersection clipping error: {:?}", err);
-                        vec![]
-                    }
-                }
+        .map(|&(_, _, ref path)| path.clone())
+        .collect::<Vec<_>>();
 
-            }
-        });
+    let mut intersection_prototypes: Vec<_> =
+        intersection_shapes_for_junctions(&gesture_shapes, &road_paths)
+            .into_iter()
+            .map(|shape| {
+                // sample the intersection's own footprint for its elevation,
+                // so it's known before any lane has been trimmed against it
+                let elevation_samples = elevation_profile_for(shape.outline(), &height_at);
+                let elevation =
+                    elevation_samples.iter().sum::<N>() / elevation_samples.len() as N;
 
-    let mut intersection_prototypes: Vec<_> = intersection_shapes
-        .map(|shape| {
-            Prototype::Road(RoadPrototype::Intersection(IntersectionPrototype {
-                shape: shape,
-                incoming: CVec::new(),
-                outgoing: CVec::new(),
-                connecting_lanes: CVec::new(),
-                timings: CVec::new(),
-            }))
-        })
-        .collect();
+                Prototype::Road(RoadPrototype::Intersection(IntersectionPrototype {
+                    shape: shape,
+                    incoming: CVec::new(),
+                    outgoing: CVec::new(),
+                    connecting_lanes: CVec::new(),
+                    timings: CVec::new(),
+                    elevation,
+                }))
+            })
+            .collect();
 
     let lane_prototypes = {
         let raw_lane_paths = gesture_intent_smooth_paths.iter().enumerate().flat_map(
-            |(lane_i, &(_, road_intent, ref path))| {
-                (0..road_intent.n_lanes_forward)
-                    .into_iter()
-                    .map(|lane_i| {
-                        CENTER_LANE_DISTANCE / 2.0 + lane_i as f32 * LANE_DISTANCE
+            |(road_i, &(_, road_intent, ref path))| {
+                road_intent
+                    .lane_types_forward
+                    .iter()
+                    .enumerate()
+                    .map(|(lane_i, &lane_type)| {
+                        (CENTER_LANE_DISTANCE / 2.0 + lane_i as N * LANE_DISTANCE, lane_type)
                     })
-                    .chain((0..road_intent.n_lanes_backward).into_iter().map(
-                        |lane_i| {
-                            -(CENTER_LANE_DISTANCE / 2.0 + lane_i as f32 * LANE_DISTANCE)
+                    .chain(road_intent.lane_types_backward.iter().enumerate().map(
+                        |(lane_i, &lane_type)| {
+                            (-(CENTER_LANE_DISTANCE / 2.0 + lane_i as N * LANE_DISTANCE), lane_type)
                         },
                     ))
-                    .filter_map(|offset| path.shift_orthogonally(offset))
+                    .filter_map(|(offset, lane_type)| {
+                        path.shift_orthogonally(offset).map(|shifted| (shifted, lane_type, road_i))
+                    })
                     .collect::<Vec<_>>()
             },
         );
 
-        let intersected_lane_paths = raw_lane_paths.into_iter().flat_map(|raw_lane_path| {
-            let mut start_trim = 0.0f32;
-            let mut end_trim = raw_lane_path.length();
-            let mut cuts = Vec::new();
+        let intersected_lane_paths: Vec<(CPath, LaneType)> =
+            raw_lane_paths.into_iter().flat_map(|(raw_lane_path, lane_type, road_i)| {
+                let mut start_trim = 0.0f32;
+                let mut end_trim = raw_lane_path.length();
+                let mut cuts = Vec::new();
 
-            for intersection in &mut intersection_prototypes {
-                if let Prototype::Road(RoadPrototype::Intersection(ref mut intersection)) =
-                    *intersection
-                {
-                    let intersection_points = (&raw_lane_path, intersection.shape.outline())
-                        .intersect();
-                    if intersection_points.len() >= 2 {
-                        let entry_distance = intersection_points
-                            .iter()
-                            .map(|p| OrderedFloat(p.along_a))
-                            .min()
-                            .unwrap();
-                        let exit_distance = intersection_points
-                            .iter()
-                            .map(|p| OrderedFloat(p.along_a))
-                            .max()
-                            .unwrap();
-                        intersection.incoming.push(IntersectionConnector(
-                            raw_lane_path.along(*entry_distance),
-                            raw_lane_path.direction_along(*entry_distance),
-                        ));
-                        intersection.outgoing.push(IntersectionConnector(
-                            raw_lane_path.along(*exit_distance),
-                            raw_lane_path.direction_along(*exit_distance),
-                        ));
-                        cuts.push((*entry_distance, *exit_distance));
-                    } else if intersection_points.len() == 1 {
-                        if intersection.shape.contains(raw_lane_path.start()) {
-                            let exit_distance = intersection_points[0].along_a;
-                            intersection.outgoing.push(IntersectionConnector(
-                                raw_lane_path.along(exit_distance),
-                                raw_lane_path.direction_along(exit_distance),
-                            ));
-                            start_trim = start_trim.max(exit_distance);
-                        } else if intersection.shape.contains(raw_lane_path.end()) {
-                            let entry_distance = intersection_points[0].along_a;
-                            intersection.incoming.push(IntersectionConnector(
-                                raw_lane_path.along(entry_distance),
-                                raw_lane_path.direction_along(entry_distance),
-                            ));
-                            end_trim = end_trim.min(entry_distance);
+                for intersection in &mut intersection_prototypes {
+                    if let Prototype::Road(RoadPrototype::Intersection(ref mut intersection)) =
+                        *intersection
+                    {
+                        let intersection_points = (&raw_lane_path, intersection.shape.outline())
+                            .intersect();
+                        if intersection_points.len() >= 2 {
+                            // the junction shape is a union of pairwise lens shapes and
+                            // isn't guaranteed convex at 3+-road junctions, so a lane can
+                            // cross its boundary more than twice - pair up crossings in
+                            // order along the lane instead of assuming a single
+                            // entry/exit, and ignore a trailing unpaired crossing rather
+                            // than guessing at it
+                            let mut crossing_distances: Vec<N> =
+                                intersection_points.iter().map(|p| p.along_a).collect();
+                            crossing_distances.sort_by_key(|&distance| OrderedFloat(distance));
+
+                            for pair in crossing_distances.chunks(2) {
+                                if let [entry_distance, exit_distance] = *pair {
+                                    intersection.incoming.push(IntersectionConnector(
+                                        raw_lane_path.along(entry_distance),
+                                        raw_lane_path.direction_along(entry_distance),
+                                        lane_type,
+                                        road_i,
+                                    ));
+                                    intersection.outgoing.push(IntersectionConnector(
+                                        raw_lane_path.along(exit_distance),
+                                        raw_lane_path.direction_along(exit_distance),
+                                        lane_type,
+                                        road_i,
+                                    ));
+                                    cuts.push((entry_distance, exit_distance));
+                                }
+                            }
+                        } else if intersection_points.len() == 1 {
+                            if intersection.shape.contains(raw_lane_path.start()) {
+                                let exit_distance = intersection_points[0].along_a;
+                                intersection.outgoing.push(IntersectionConnector(
+                                    raw_lane_path.along(exit_distance),
+                                    raw_lane_path.direction_along(exit_distance),
+                                    lane_type,
+                                    road_i,
+                                ));
+                                start_trim = start_trim.max(exit_distance);
+                            } else if intersection.shape.contains(raw_lane_path.end()) {
+                                let entry_distance = intersection_points[0].along_a;
+                                intersection.incoming.push(IntersectionConnector(
+                                    raw_lane_path.along(entry_distance),
+                                    raw_lane_path.direction_along(entry_distance),
+                                    lane_type,
+                                    road_i,
+                                ));
+                                end_trim = end_trim.min(entry_distance);
+                            }
                         }
+                    } else {
+                        unreachable!()
                     }
-                } else {
-                    unreachable!()
                 }
-            }
 
-            cuts.sort_by(|a, b| OrderedFloat(a.0).cmp(&OrderedFloat(b.0)));
+                cuts.sort_by(|a, b| OrderedFloat(a.0).cmp(&OrderedFloat(b.0)));
 
-            cuts.insert(0, (-1.0, start_trim));
-            cuts.push((end_trim, raw_lane_path.length() + 1.0));
+                cuts.insert(0, (-1.0, start_trim));
+                cuts.push((end_trim, raw_lane_path.length() + 1.0));
 
-            cuts.windows(2)
-                .filter_map(|two_cuts| {
-                    let ((_, exit_distance), (entry_distance, _)) = (two_cuts[0], two_cuts[1]);
-                    raw_lane_path.subsection(exit_distance, entry_distance)
-                })
-                .collect::<Vec<_>>()
-        });
+                cuts.windows(2)
+                    .filter_map(|two_cuts| {
+                        let ((_, exit_distance), (entry_distance, _)) = (two_cuts[0], two_cuts[1]);
+                        raw_lane_path.subsection(exit_distance, entry_distance)
+                    })
+                    .map(|path| (path, lane_type))
+                    .collect::<Vec<_>>()
+            }).collect();
 
+        // intersection_prototypes is no longer mutably borrowed past this
+        // point, so its (by-now-final) elevations can be read while snapping
         intersected_lane_paths
             .into_iter()
-            .map(|path| {
-                Prototype::Road(RoadPrototype::Lane(LanePrototype(path)))
+            .map(|(path, lane_type)| {
+                let elevation_profile = snap_elevation_profile_ends(
+                    elevation_profile_for(&path, &height_at),
+                    &path,
+                    &intersection_prototypes,
+                );
+                Prototype::Road(RoadPrototype::Lane(LanePrototype(path, elevation_profile, lane_type)))
             })
             .collect::<Vec<_>>()
     };
 
+    let mut sidewalk_corner_shapes = Vec::new();
+
+    for intersection in &mut intersection_prototypes {
+        if let Prototype::Road(RoadPrototype::Intersection(ref mut intersection)) = *intersection {
+            intersection.connecting_lanes = connecting_lanes_for(
+                &intersection.incoming,
+                &intersection.outgoing,
+                intersection.elevation,
+            );
+            intersection.timings = phase_timings_for(&intersection.connecting_lanes);
+            sidewalk_corner_shapes.extend(sidewalk_corner_shapes_for(intersection));
+        } else {
+            unreachable!()
+        }
+    }
+
+    let sidewalk_corner_prototypes = sidewalk_corner_shapes
+        .into_iter()
+        .map(|shape| Prototype::Road(RoadPrototype::SidewalkCorner(shape)))
+        .collect::<Vec<_>>();
+
     intersection_prototypes
         .into_iter()
         .chain(lane_prototypes)
+        .chain(sidewalk_corner_prototypes)
         .collect()
 }
 
+fn elevation_sample_positions(path: &CPath, n_samples: usize) -> Vec<P2> {
+    let length = path.length();
+    let divisions = (n_samples - 1).max(1);
+    (0..n_samples).map(|i| path.along((i as N / divisions as N) * length)).collect()
+}
+
+fn nearest_elevation(sample_positions: &[P2], elevation: &[N], point: P2) -> N {
+    sample_positions
+        .iter()
+        .zip(elevation.iter())
+        .min_by_key(|&(sample_position, _)| OrderedFloat((*sample_position - point).norm()))
+        .map(|(_, &height)| height)
+        .unwrap_or(0.0)
+}
+
 pub fn render_preview(
     result_preview: &PlanResult,
     renderer_id: RendererID,
@@ -292,25 +890,44 @@ pub fn render_preview(
 ) {
     for (i, prototype) in result_preview.prototypes.iter().enumerate() {
         match *prototype {
-            Prototype::Road(RoadPrototype::Lane(LanePrototype(ref lane_path))) => {
-                let line_geometry =
+            Prototype::Road(RoadPrototype::Lane(LanePrototype(ref lane_path, ref elevation, lane_type))) => {
+                let mut line_geometry =
                     band_to_geometry(&Band::new(lane_path.clone(), LANE_WIDTH * 0.7), 0.1);
 
+                let sample_positions = elevation_sample_positions(lane_path, elevation.len());
+
+                for vertex in &mut line_geometry.vertices {
+                    let vertex_position = P2::new(vertex.position[0], vertex.position[1]);
+                    vertex.position[2] =
+                        nearest_elevation(&sample_positions, elevation, vertex_position);
+                }
+
+                let color = match lane_type {
+                    LaneType::Driving | LaneType::Parking => colors::STROKE_BASE,
+                    LaneType::Sidewalk | LaneType::Shoulder => colors::SELECTION_STROKE,
+                };
+
                 renderer_id.update_individual(
                     scene_id,
                     18_000 + i as u16,
                     line_geometry,
-                    Instance::with_color(colors::STROKE_BASE),
+                    Instance::with_color(color),
                     true,
                     world,
                 );
             }
             Prototype::Road(RoadPrototype::Intersection(IntersectionPrototype {
-                                                            ref shape, ..
+                                                            ref shape,
+                                                            elevation,
+                                                            ..
                                                         })) => {
-                let outline_geometry =
+                let mut outline_geometry =
                     band_to_geometry(&Band::new(shape.outline().clone(), 0.1), 0.1);
 
+                for vertex in &mut outline_geometry.vertices {
+                    vertex.position[2] = elevation;
+                }
+
                 renderer_id.update_individual(
                     scene_id,
                     18_500 + i as u16,
@@ -320,7 +937,141 @@ pub fn render_preview(
                     world,
                 );
             }
+            Prototype::Road(RoadPrototype::SidewalkCorner(ref shape)) => {
+                let corner_geometry = band_to_geometry(&Band::new(shape.outline().clone(), 0.1), 0.1);
+
+                renderer_id.update_individual(
+                    scene_id,
+                    19_000 + i as u16,
+                    corner_geometry,
+                    Instance::with_color(colors::SELECTION_STROKE),
+                    true,
+                    world,
+                );
+            }
             _ => {}
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connecting_path_reaches_both_endpoints_even_when_no_direct_arc_fits() {
+        // the outgoing tangent points the same way as the incoming one, but
+        // p_out lies straight to the side, so the only arc leaving along
+        // dir_in (a half circle) arrives heading the opposite way - no single
+        // arc respects both tangents and the fallback control-point curve has
+        // to be used instead
+        let p_in = P2::new(0.0, 0.0);
+        let dir_in = V2::new(1.0, 0.0);
+        let p_out = P2::new(0.0, 10.0);
+        let dir_out = V2::new(1.0, 0.0);
+
+        let path = connecting_path(p_in, dir_in, p_out, dir_out)
+            .expect("a connecting curve should always be constructible between two connectors");
+
+        assert!((path.start() - p_in).norm() < 0.01);
+        assert!((path.end() - p_out).norm() < 0.01);
+    }
+
+    #[test]
+    fn connecting_path_falls_back_to_a_straight_line_when_the_control_curve_degenerates() {
+        // the two points are nearly coincident, so the control-point
+        // fallback's middle segment collapses to zero length and only the
+        // final straight-line fallback can still connect them
+        let p_in = P2::new(0.0, 0.0);
+        let dir_in = V2::new(1.0, 0.0);
+        let p_out = P2::new(0.0001, 0.0);
+        let dir_out = V2::new(-1.0, 0.0);
+
+        let path = connecting_path(p_in, dir_in, p_out, dir_out)
+            .expect("a connecting curve should always be constructible between distinct points");
+
+        assert!((path.start() - p_in).norm() < 0.01);
+        assert!((path.end() - p_out).norm() < 0.01);
+    }
+
+    #[test]
+    fn is_right_turn_like_rejects_straight_and_left_but_accepts_right() {
+        let straight = CPath::new(vec![
+            Segment::line(P2::new(0.0, 0.0), P2::new(10.0, 0.0)).unwrap(),
+        ]).unwrap();
+        assert!(!is_right_turn_like(&straight));
+
+        let left_turn = CPath::new(vec![
+            Segment::line(P2::new(0.0, 0.0), P2::new(10.0, 0.0)).unwrap(),
+            Segment::line(P2::new(10.0, 0.0), P2::new(10.0, 10.0)).unwrap(),
+        ]).unwrap();
+        assert!(!is_right_turn_like(&left_turn));
+
+        let right_turn = CPath::new(vec![
+            Segment::line(P2::new(0.0, 0.0), P2::new(10.0, 0.0)).unwrap(),
+            Segment::line(P2::new(10.0, 0.0), P2::new(10.0, -10.0)).unwrap(),
+        ]).unwrap();
+        assert!(is_right_turn_like(&right_turn));
+    }
+
+    #[test]
+    fn phase_timings_for_separates_conflicting_movements_into_different_phases() {
+        // two straight-through lanes crossing each other at a right angle
+        // conflict and must not share a phase
+        let lane_a = LanePrototype(
+            CPath::new(vec![Segment::line(P2::new(-10.0, 0.0), P2::new(10.0, 0.0)).unwrap()])
+                .unwrap(),
+            vec![0.0, 0.0].into(),
+            LaneType::Driving,
+        );
+        let lane_b = LanePrototype(
+            CPath::new(vec![Segment::line(P2::new(0.0, -10.0), P2::new(0.0, 10.0)).unwrap()])
+                .unwrap(),
+            vec![0.0, 0.0].into(),
+            LaneType::Driving,
+        );
+
+        let connecting_lanes: CVec<LanePrototype> = vec![lane_a, lane_b].into();
+        let timings = phase_timings_for(&connecting_lanes);
+
+        assert!(timings.iter().all(|phase| !(phase[0] && phase[1])));
+    }
+
+    #[test]
+    fn curved_path_starts_and_ends_at_the_given_points() {
+        let points = vec![P2::new(0.0, 0.0), P2::new(10.0, 0.0), P2::new(20.0, 10.0)];
+        let start_tangent = V2::new(1.0, 0.0);
+
+        let path = curved_path(&points, start_tangent, None)
+            .expect("a curve should be constructible through these points");
+
+        assert!((path.start() - points[0]).norm() < 0.01);
+        assert!((path.end() - points[2]).norm() < 0.01);
+    }
+
+    #[test]
+    fn curved_path_stays_continuous_when_an_interior_arc_cannot_honor_the_tangent() {
+        // the tangent points back the way the first segment came from, so no
+        // arc can honor it into the second point - the gap must be bridged
+        // with a straight line instead of left open
+        let points = vec![P2::new(0.0, 0.0), P2::new(0.0, 10.0), P2::new(10.0, 10.0)];
+        let start_tangent = V2::new(0.0, -1.0);
+
+        let path = curved_path(&points, start_tangent, None)
+            .expect("the path must stay continuous even when an arc can't be built");
+
+        assert!((path.start() - points[0]).norm() < 0.01);
+        assert!((path.end() - points[2]).norm() < 0.01);
+    }
+
+    #[test]
+    fn union_junctions_merges_transitively_linked_endpoints_into_one_root() {
+        let mut parents = (0..4).collect::<Vec<_>>();
+
+        union_junctions(&mut parents, 0, 1);
+        union_junctions(&mut parents, 1, 2);
+
+        assert_eq!(find_root(&mut parents, 0), find_root(&mut parents, 2));
+        assert_ne!(find_root(&mut parents, 0), find_root(&mut parents, 3));
+    }
 }
\ No newline at end of file